@@ -0,0 +1,60 @@
+//! TOML-backed configuration for the repository server, merged with
+//! command-line overrides in `main`.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+/// On-disk configuration, as parsed from the file passed via `--config`.
+/// Every field is optional here: anything left unset can still be supplied
+/// on the command line. Of the path and address fields, `cache_dir` and `bind`
+/// fall back to a hardcoded default in `main` when left unset altogether;
+/// `addons_dir` and `listing` have no default, and `main` reports an error
+/// when neither source gives them.
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct Config {
+    pub(crate) bind: Option<SocketAddr>,
+    pub(crate) addons_dir: Option<PathBuf>,
+    pub(crate) listing: Option<PathBuf>,
+    pub(crate) cache_dir: Option<PathBuf>,
+    /// Compression codings the server is allowed to negotiate with clients.
+    /// Empty (the default) means all of them.
+    #[serde(default)]
+    pub(crate) compression: Vec<String>,
+    /// IP-based access control. Defaults to allowing every address.
+    #[serde(default)]
+    pub(crate) acl: AclConfig,
+}
+
+/// IP allow/deny list configuration.
+#[derive(Debug, Default, serde::Deserialize)]
+pub(crate) struct AclConfig {
+    /// CIDR ranges allowed to reach the server. Empty (the default) means
+    /// every address is allowed, subject to `deny` below.
+    #[serde(default)]
+    pub(crate) allow: Vec<String>,
+    /// CIDR ranges denied regardless of `allow`.
+    #[serde(default)]
+    pub(crate) deny: Vec<String>,
+    /// Trust `X-Forwarded-For` to carry the real client address (e.g. when
+    /// running behind a reverse proxy), instead of using the socket peer
+    /// address directly.
+    #[serde(default)]
+    pub(crate) trust_proxy: bool,
+}
+
+impl Config {
+    /// Load `path`, or fall back to an all-default config if no `--config`
+    /// flag was given at all.
+    pub(crate) fn load(path: Option<&Path>) -> anyhow::Result<Config> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("couldn't read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("config file {} was not valid TOML", path.display()))
+    }
+}
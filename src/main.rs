@@ -1,22 +1,26 @@
 //! A Kodi repository server, with specific support for serving addons straight out of Git
 //! repositories. Uses an extra directory on disk to cache `.zip` files.
 
-use std::borrow::Cow;
-use std::ffi::OsStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::fs;
 
+mod config;
+
 const IDS_XPATH: &str = "/addons/addon/@id";
+const ADDON_XPATH: &str = "/addon";
+const VERSION_XPATH: &str = "/addon/@version";
 
 /// Retrieve addon IDs from repository addon listing file.
 // TODO: examine how much work it'd be to
 // support non UTF-8 manifests.
-fn get_ids(listing: &Path) -> Vec<String> {
+fn get_ids(listing: &Path) -> anyhow::Result<Vec<String>> {
+    use anyhow::Context;
     let package = sxd_document::parser::parse(
-        &fs::read_to_string(listing).expect("couldn't read listing file"))
-        .expect("listing file was invalid XML");
+        &fs::read_to_string(listing).with_context(|| format!("couldn't read listing file {}", listing.display()))?)
+        .with_context(|| format!("listing file {} was invalid XML", listing.display()))?;
     let document = package.as_document();
-    let value = sxd_xpath::evaluate_xpath(&document, IDS_XPATH).expect("failed XPath evaluation");
+    let value = sxd_xpath::evaluate_xpath(&document, IDS_XPATH)
+        .with_context(|| format!("failed XPath evaluation of {:?}", IDS_XPATH))?;
     println!("IDs: {:?}", value);
     use sxd_xpath::Value;
     use sxd_xpath::nodeset::Node;
@@ -24,28 +28,80 @@ fn get_ids(listing: &Path) -> Vec<String> {
         Value::Nodeset(set) => {
             set.iter().map(|node| {
                 match node {
-                    Node::Attribute(attr) => attr.value().to_owned(),
-                    node => panic!("invalid node type from xpath evaluation: {:?}", node),
+                    Node::Attribute(attr) => Ok(attr.value().to_owned()),
+                    node => anyhow::bail!("invalid node type from xpath evaluation: {:?}", node),
                 }
             }).collect()
         }
-        val => panic!("invalid value type from xpath evaluation: {:?}", val),
+        val => anyhow::bail!("invalid value type from xpath evaluation: {:?}", val),
     }
 }
 
 #[cfg(feature = "server")]
 #[tokio::main]
-async fn serve(addons_dir: &Path, listing: &Path, cache_dir: &Path) {
+async fn serve(addons_dir: &Path, listing: &Path, cache_dir: &Path, bind: std::net::SocketAddr, compression: &[String], acl: &config::AclConfig) -> anyhow::Result<()> {
+    use anyhow::Context;
     use warp::Filter;
-    use warp::Reply;
-    use std::net::SocketAddr;
     use std::sync::Arc;
+    use arc_swap::ArcSwap;
+    use tokio::signal::unix::{signal, SignalKind};
     // We avoid hitting the filesystem on invalid requests for a number of reasons.
-    let ids = Arc::new(get_ids(listing));
-    let socket_addr: SocketAddr = "127.0.0.1:9001".parse().unwrap();
+    // Held behind an `ArcSwap` rather than a plain `Arc` so a SIGHUP can swap in a
+    // freshly re-read listing without taking a lock on the request path.
+    let ids = Arc::new(ArcSwap::from_pointee(get_ids(listing)?));
+    let addons_dir = addons_dir.to_path_buf();
+    let cache_dir = cache_dir.to_path_buf();
+    let listing = listing.to_path_buf();
+    let socket_addr = bind;
+    let allowed = Arc::new(server::parse_allowed_encodings(compression));
+    let acl = Arc::new(server::Acl::parse(acl)?);
+
+    // On SIGHUP, re-read and re-parse the listing file and swap in the new ID
+    // set, without disturbing requests already in flight.
+    let reload_ids = ids.clone();
+    tokio::spawn(async move {
+        let result: anyhow::Result<()> = async {
+            let mut hangup = signal(SignalKind::hangup()).context("couldn't install SIGHUP handler")?;
+            loop {
+                hangup.recv().await;
+                println!("SIGHUP received, reloading {}", listing.display());
+                match get_ids(&listing) {
+                    Ok(ids) => reload_ids.store(Arc::new(ids)),
+                    Err(e) => eprintln!("failed to reload {}: {:#}", listing.display(), e),
+                }
+            }
+        }.await;
+        if let Err(e) = result {
+            eprintln!("SIGHUP reload handler died: {:#}", e);
+        }
+    });
+
+    // On SIGTERM/SIGINT, stop accepting new connections but let in-flight
+    // requests finish before the process exits.
+    let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let result: anyhow::Result<()> = async {
+            let mut terminate = signal(SignalKind::terminate()).context("couldn't install SIGTERM handler")?;
+            let mut interrupt = signal(SignalKind::interrupt()).context("couldn't install SIGINT handler")?;
+            tokio::select! {
+                _ = terminate.recv() => {}
+                _ = interrupt.recv() => {}
+            }
+            // The receiving end may already be gone if the server has shut down for
+            // some other reason; there's nothing left to do in that case either.
+            let _ = shutdown_tx.send(());
+            Ok(())
+        }.await;
+        if let Err(e) = result {
+            eprintln!("graceful shutdown handler died: {:#}", e);
+        }
+    });
+
     // Note, type errors here are much more helpful
     // when the closure argument type is annotated.
-    let filter = warp::path!("addons" / String).and_then(move |id: String| {
+    // Reject disallowed clients before the route even looks at the filesystem
+    // or cache.
+    let zip = server::enforce_acl(acl).and(warp::path!("addons" / String).and_then(move |id: String| {
         // Workaround what may be a borrow checker limitation.
         // I do happen to know that `Server::run` never returns,
         // so the initial `ids` binding never goes out of scope,
@@ -54,96 +110,736 @@ async fn serve(addons_dir: &Path, listing: &Path, cache_dir: &Path) {
         // Given that, I could use raw pointers to force the issue.
         // But, I really don't feel like worrying about more `unsafe` than I have to.
         let ids = ids.clone();
+        let addons_dir = addons_dir.clone();
+        let cache_dir = cache_dir.clone();
         async move {
-            if ids.contains(&id) {
-                Ok(Cow::Owned(format!("{} exists!", id)))
-            } else {
-                Err(warp::reject::not_found())
+            if !ids.load().contains(&id) {
+                return Err(warp::reject::not_found());
             }
+            // Building and hashing the addon's zip is blocking filesystem work;
+            // don't do it on the async executor's thread.
+            tokio::task::spawn_blocking(move || server::build_or_fetch_zip(&addons_dir, &cache_dir, &id))
+                .await
+                .expect("zip-building task panicked")
         }
-    }).and(warp::header::optional("compression")).map(|processed, compression: Option<bool>| {
-        (processed, compression.unwrap_or(false))
-    }).and_then(|(processed, compression)| async move {
-        if compression {
-            Ok(processed)
-        } else {
-            Err(warp::reject::custom(server::NoGzipW::new(processed)))
-        }
-    }).with(warp::compression::gzip()).recover(server::handle_no_gzip::<Cow<'_, str>>);
-    warp::serve(filter).run(socket_addr).await;
+    }));
+    // Negotiate `Accept-Encoding` the normal HTTP way instead of unconditionally
+    // gzipping every response: gate each encoding's filter on it actually being
+    // the client's best match, falling through `.or()` to the next candidate,
+    // down to identity if nothing listed is acceptable.
+    let filter = server::accepts(server::Encoding::Brotli, allowed.clone()).and(zip.clone()).with(warp::compression::brotli())
+        .or(server::accepts(server::Encoding::Gzip, allowed.clone()).and(zip.clone()).with(warp::compression::gzip()))
+        .or(server::accepts(server::Encoding::Deflate, allowed.clone()).and(zip.clone()).with(warp::compression::deflate()))
+        .or(zip)
+        .recover(server::handle_rejection);
+    let (_, server) = warp::serve(filter).bind_with_graceful_shutdown(socket_addr, async move {
+        shutdown_rx.await.ok();
+    });
+    server.await;
+    Ok(())
 }
 
 #[cfg(feature = "server")]
 mod server {
     use warp::Filter;
-    use warp::reject::{Reject, Rejection};
-    use warp::Reply;
-    use core::mem::ManuallyDrop;
+    use warp::reject::Rejection;
+    use std::path::{Path, PathBuf};
+    use std::fs;
+    use std::net::IpAddr;
+    use warp::reply::Response;
+
+    /// A client the ACL has decided not to serve.
+    #[derive(Debug)]
+    struct Forbidden;
+    impl warp::reject::Reject for Forbidden {}
+
+    /// Parsed IP allow/deny list, ready to check requests against.
+    #[derive(Debug)]
+    pub(crate) struct Acl {
+        allow: Vec<ipnet::IpNet>,
+        deny: Vec<ipnet::IpNet>,
+        trust_proxy: bool,
+    }
+
+    impl Acl {
+        pub(crate) fn parse(config: &crate::config::AclConfig) -> anyhow::Result<Acl> {
+            use anyhow::Context;
+            let parse_list = |entries: &[String]| -> anyhow::Result<Vec<ipnet::IpNet>> {
+                entries.iter()
+                    .map(|entry| entry.parse::<ipnet::IpNet>()
+                        .with_context(|| format!("invalid CIDR range {:?}", entry)))
+                    .collect()
+            };
+            Ok(Acl {
+                allow: parse_list(&config.allow)?,
+                deny: parse_list(&config.deny)?,
+                trust_proxy: config.trust_proxy,
+            })
+        }
+
+        fn permits(&self, addr: IpAddr) -> bool {
+            if self.deny.iter().any(|net| net.contains(&addr)) {
+                return false;
+            }
+            self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&addr))
+        }
+    }
+
+    /// Resolve the address a request should be judged against: the socket
+    /// peer address, or (when `trust_proxy` is set) the right-most entry of
+    /// `X-Forwarded-For`, which is the address the nearest reverse proxy
+    /// reported seeing the request come from.
+    fn resolve_client_addr(remote: Option<std::net::SocketAddr>, forwarded_for: Option<&str>, trust_proxy: bool) -> Option<IpAddr> {
+        if trust_proxy {
+            if let Some(addr) = forwarded_for
+                .and_then(|header| header.split(',').map(str::trim).filter(|s| !s.is_empty()).last())
+                .and_then(|entry| entry.parse().ok())
+            {
+                return Some(addr);
+            }
+        }
+        remote.map(|addr| addr.ip())
+    }
+
+    /// A filter that passes through (extracting nothing) for clients the ACL
+    /// allows, and otherwise rejects with `Forbidden` before anything
+    /// downstream touches the filesystem or cache.
+    pub(crate) fn enforce_acl(acl: std::sync::Arc<Acl>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        warp::filters::addr::remote()
+            .and(warp::header::optional::<String>("x-forwarded-for"))
+            .and_then(move |remote: Option<std::net::SocketAddr>, forwarded_for: Option<String>| {
+                let acl = acl.clone();
+                async move {
+                    match resolve_client_addr(remote, forwarded_for.as_deref(), acl.trust_proxy) {
+                        Some(addr) if acl.permits(addr) => Ok(()),
+                        _ => Err(warp::reject::custom(Forbidden)),
+                    }
+                }
+            })
+            .untuple_one()
+    }
+
+    /// Turn a `Forbidden` rejection into a bare 403 and a `ServerError` into a
+    /// bare 500 (logging the underlying cause), leaving warp's normal 404
+    /// handling for everything else.
+    pub(crate) async fn handle_rejection(err: Rejection) -> Result<impl warp::Reply, Rejection> {
+        if err.find::<Forbidden>().is_some() {
+            Ok(warp::reply::with_status(warp::reply(), warp::http::StatusCode::FORBIDDEN))
+        } else if let Some(ServerError(e)) = err.find::<ServerError>() {
+            eprintln!("error serving request: {:#}", e);
+            Ok(warp::reply::with_status(warp::reply(), warp::http::StatusCode::INTERNAL_SERVER_ERROR))
+        } else {
+            Err(err)
+        }
+    }
+
+    /// Hash `id` together with the content of every file under `dir` (path and
+    /// bytes), giving a digest of the addon's current source tree. This is the
+    /// cache key: as long as `id` and the addon's directory contents are
+    /// unchanged, the digest is stable and a previously-built zip can be served
+    /// straight from `cache_dir`. `id` is folded in so two addons with
+    /// byte-identical file trees (a duplicated or templated addon directory)
+    /// never alias the same cache entry, since the zip's top-level directory
+    /// name is `id`, not the digest.
+    ///
+    /// Symlinks are skipped rather than followed: `WalkDir` doesn't traverse
+    /// into them, and hashing whatever they happen to point at (possibly
+    /// outside `dir` entirely) would make the cache key depend on files this
+    /// addon doesn't actually own.
+    fn hash_tree(id: &str, dir: &Path) -> anyhow::Result<String> {
+        use anyhow::Context;
+        use sha2::{Digest, Sha256};
+        let mut hasher = Sha256::new();
+        hasher.update(id.as_bytes());
+        hasher.update(b"\0");
+        let mut paths: Vec<PathBuf> = walkdir::WalkDir::new(dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file() && !entry.path_is_symlink())
+            .map(|entry| entry.path().to_path_buf())
+            .collect();
+        paths.sort();
+        for path in &paths {
+            let rel = path.strip_prefix(dir).expect("walked path was under dir");
+            hasher.update(rel.to_string_lossy().as_bytes());
+            hasher.update(fs::read(path).with_context(|| format!("couldn't read addon source file {}", path.display()))?);
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Build a Kodi-style addon zip: every file under `source_dir`, rooted at
+    /// a single top-level `{id}/` directory inside the archive, as Kodi expects.
+    ///
+    /// Symlinks are skipped rather than followed: `source_dir` itself is the
+    /// only path `url_to_path` has validated against escaping `addons_dir`, and
+    /// a symlink discovered while walking it could otherwise point anywhere on
+    /// the host's filesystem (a sibling addon's private files, `/etc/passwd`,
+    /// ...) and get read straight into the served zip.
+    fn build_addon_zip(id: &str, source_dir: &Path) -> anyhow::Result<Vec<u8>> {
+        use anyhow::Context;
+        use std::io::Write;
+        use zip::write::FileOptions;
+        let mut buf = Vec::new();
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(&mut buf));
+        let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        let mut entries: Vec<walkdir::DirEntry> = walkdir::WalkDir::new(source_dir)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.path_is_symlink())
+            .collect();
+        entries.sort_by(|a, b| a.path().cmp(b.path()));
+        for entry in entries {
+            let path = entry.path();
+            let rel = path.strip_prefix(source_dir).expect("walked path was under source_dir");
+            let zip_path = format!("{}/{}", id, rel.to_string_lossy());
+            if entry.file_type().is_dir() {
+                writer.add_directory(format!("{}/", zip_path), options)
+                    .with_context(|| format!("couldn't add zip directory entry {}", zip_path))?;
+            } else {
+                writer.start_file(&zip_path, options)
+                    .with_context(|| format!("couldn't start zip file entry {}", zip_path))?;
+                writer.write_all(&fs::read(path).with_context(|| format!("couldn't read addon source file {}", path.display()))?)
+                    .with_context(|| format!("couldn't write zip file entry {}", zip_path))?;
+            }
+        }
+        writer.finish().context("couldn't finalize zip archive")?;
+        Ok(buf)
+    }
+
+    /// An error that happened while serving a request, turned into a 500 by
+    /// [`handle_rejection`].
     #[derive(Debug)]
-    pub(crate) struct NoGzipW<T> {
-        // TODO: consider using UnsafeCell?
-        // I don't need to mutate while it's held- only to steal it right before
-        // the thing holding it drops.
-        // I only get an immutable reference to `NoGzipW`,
-        // hence my not using `Option` with `.take()` or something.
-        never_drop: ManuallyDrop<T>
-    }
-    impl<T> NoGzipW<T> {
-        pub(crate) fn new(val: T) -> Self {
-            Self {
-                never_drop: ManuallyDrop::new(val),
-            }
-        }
-    }
-    impl<T: core::fmt::Debug + Send + Sync + 'static> Reject for NoGzipW<T> {}
-    pub(crate) async fn handle_no_gzip<T: Reply + 'static>(reject: Rejection) -> Result<T, Rejection> {
-            match reject.find::<NoGzipW<T>>() {
-                Some(x) => {
-                    // Important Note: If this breaks, I definitely have zero right to complain.
-                    // I should look into getting what I need for this to be stably sound
-                    // into the `warp` crate.
-                    // That said, global reasoning of this crate will not stop being correct.
-                    // It is only that `warp` may change to make this *require* that global
-                    // reasoning to be done.
-                    // Safety: This relies somewhat on an implementation detail of `warp`.
-                    // That is, we assume that holding a `Rejection` means we hold a unique
-                    // owning pointer to the underlying cause.
-                    // This is true at the time of writing (warp 0.3.0), as `Rejection` stores
-                    // custom causes in a `Box<dyn Cause>`, with no shared ownership in sight.
-                    // Therefore, if we ensure `reject` is not used after this, and that
-                    // the stored duplicate inside of `reject` does not run any existing `Drop`
-                    // implementation, no logical invariants will be broken.
-                    // We ensure that no `Drop` implementation is run via the use of `ManuallyDrop`
-                    // inside of `NoGzipW`.
-                    Ok(unsafe { ::std::ptr::read(&*x.never_drop) })
-                },
-                None => Err(reject)
+    struct ServerError(anyhow::Error);
+    impl warp::reject::Reject for ServerError {}
+
+    fn server_error(e: anyhow::Error) -> Rejection {
+        warp::reject::custom(ServerError(e))
+    }
+
+    /// Resolve `id` to a path under `root`, the single chokepoint every
+    /// request-derived filesystem path goes through. Joins `id` onto `root`,
+    /// canonicalizes the result, and rejects anything that doesn't still live
+    /// under the canonicalized `root` — so a `..` or absolute component in
+    /// `id` can't be used to escape `addons_dir`/`cache_dir`.
+    fn url_to_path(root: &Path, id: &str) -> Option<PathBuf> {
+        let root = root.canonicalize().ok()?;
+        let candidate = root.join(id).canonicalize().ok()?;
+        candidate.starts_with(&root).then_some(candidate)
+    }
+
+    /// Serve the zip archive for `id`, from `cache_dir` on a hash hit, or by
+    /// building it from `addons_dir` and caching the result on a miss.
+    pub(crate) fn build_or_fetch_zip(addons_dir: &Path, cache_dir: &Path, id: &str) -> Result<Response, Rejection> {
+        use anyhow::Context;
+        let source_dir = match url_to_path(addons_dir, id) {
+            Some(path) if path.is_dir() => path,
+            _ => return Err(warp::reject::not_found()),
+        };
+        let digest = hash_tree(id, &source_dir).map_err(server_error)?;
+        let cached_path = cache_dir.join(format!("{}.zip", digest));
+        let bytes = match fs::read(&cached_path) {
+            Ok(bytes) => bytes,
+            Err(_) => {
+                let bytes = build_addon_zip(id, &source_dir).map_err(server_error)?;
+                fs::create_dir_all(cache_dir)
+                    .with_context(|| format!("couldn't create cache directory {}", cache_dir.display()))
+                    .map_err(server_error)?;
+                // Write to a temp file first and rename into place, so a concurrent
+                // request never observes a partially-written cache entry.
+                // The counter keeps concurrent requests within this process from
+                // sharing (and clobbering) one temp file.
+                static TMP_COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+                let n = TMP_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let tmp_path = cache_dir.join(format!("{}.zip.tmp-{}-{}", digest, std::process::id(), n));
+                fs::write(&tmp_path, &bytes)
+                    .with_context(|| format!("couldn't write zip to cache at {}", tmp_path.display()))
+                    .map_err(server_error)?;
+                fs::rename(&tmp_path, &cached_path)
+                    .with_context(|| format!("couldn't finalize cached zip at {}", cached_path.display()))
+                    .map_err(server_error)?;
+                bytes
+            }
+        };
+        let manifest = source_dir.join("addon.xml");
+        let version = super::addon_version(&manifest).map_err(server_error)?;
+        warp::http::Response::builder()
+            .header("Content-Type", "application/zip")
+            .header("Content-Disposition", format!("attachment; filename=\"{}-{}.zip\"", id, version))
+            .body(bytes.into())
+            .context("couldn't build zip response")
+            .map_err(server_error)
+    }
+
+    /// Content codings we can produce, in the order we prefer them when a
+    /// client's `Accept-Encoding` expresses no real preference.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(crate) enum Encoding {
+        Brotli,
+        Gzip,
+        Deflate,
+        Identity,
+    }
+
+    impl Encoding {
+        fn from_name(name: &str) -> Option<Encoding> {
+            match name {
+                "br" => Some(Encoding::Brotli),
+                "gzip" => Some(Encoding::Gzip),
+                "deflate" => Some(Encoding::Deflate),
+                "identity" | "*" => Some(Encoding::Identity),
+                _ => None,
+            }
+        }
+    }
+
+    /// Parse the `compression` list from the config/CLI into the codings the
+    /// server is allowed to negotiate. An empty list (the default) means all
+    /// of them; `identity` is always implicitly allowed as the fallback.
+    pub(crate) fn parse_allowed_encodings(names: &[String]) -> Vec<Encoding> {
+        let mut allowed: Vec<Encoding> = if names.is_empty() {
+            vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate]
+        } else {
+            names.iter()
+                .filter_map(|name| Encoding::from_name(&name.to_ascii_lowercase()))
+                .collect()
+        };
+        if !allowed.contains(&Encoding::Identity) {
+            allowed.push(Encoding::Identity);
+        }
+        allowed
+    }
+
+    /// Parse an `Accept-Encoding` header value and pick the best coding that
+    /// is both requested and in `allowed`, honoring `q` weights and falling
+    /// back to identity when nothing listed is acceptable (or the header is
+    /// absent).
+    fn negotiate(header: Option<&str>, allowed: &[Encoding]) -> Encoding {
+        let header = match header {
+            Some(header) => header,
+            None => return Encoding::Identity,
+        };
+        let mut best: Option<(Encoding, f32)> = None;
+        for candidate in header.split(',') {
+            let mut parts = candidate.split(';').map(str::trim);
+            let name = match parts.next() {
+                Some(name) if !name.is_empty() => name.to_ascii_lowercase(),
+                _ => continue,
+            };
+            let q = parts
+                .find_map(|param| param.strip_prefix("q=").and_then(|v| v.parse::<f32>().ok()))
+                .unwrap_or(1.0);
+            if q <= 0.0 {
+                continue;
+            }
+            let encoding = match Encoding::from_name(&name) {
+                Some(encoding) if allowed.contains(&encoding) => encoding,
+                _ => continue,
+            };
+            let is_better = match best {
+                None => true,
+                // On equal weights, fall back to our own preference order.
+                Some((best_encoding, best_q)) => q > best_q || (q == best_q && (encoding as u8) < (best_encoding as u8)),
+            };
+            if is_better {
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding).unwrap_or(Encoding::Identity)
+    }
+
+    /// A filter that passes through (extracting nothing) only when `target`
+    /// is the client's negotiated best `Accept-Encoding` match (and `target`
+    /// is itself enabled), and rejects otherwise so an enclosing `.or()`
+    /// chain falls through to the next candidate encoding.
+    pub(crate) fn accepts(target: Encoding, allowed: std::sync::Arc<Vec<Encoding>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        warp::header::optional::<String>("accept-encoding")
+            .and_then(move |header: Option<String>| {
+                let allowed = allowed.clone();
+                async move {
+                    if negotiate(header.as_deref(), &allowed) == target {
+                        Ok(())
+                    } else {
+                        Err(warp::reject::not_found())
+                    }
+                }
+            })
+            .untuple_one()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::net::SocketAddr;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        /// A fresh, empty directory under the system temp dir, removed on drop.
+        struct TempDir(PathBuf);
+
+        impl TempDir {
+            fn new() -> TempDir {
+                static COUNTER: AtomicUsize = AtomicUsize::new(0);
+                let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+                let path = std::env::temp_dir()
+                    .join(format!("kodi-addons-test-{}-{}", std::process::id(), n));
+                fs::create_dir_all(&path).expect("couldn't create temp dir for test");
+                TempDir(path)
+            }
+        }
+
+        impl std::ops::Deref for TempDir {
+            type Target = Path;
+            fn deref(&self) -> &Path {
+                &self.0
+            }
+        }
+
+        impl Drop for TempDir {
+            fn drop(&mut self) {
+                let _ = fs::remove_dir_all(&self.0);
             }
         }
+
+        #[test]
+        fn url_to_path_rejects_dotdot_escape() {
+            let root = TempDir::new();
+            fs::create_dir(root.join("addon")).unwrap();
+            assert!(url_to_path(&root, "../etc").is_none());
+            assert!(url_to_path(&root, "addon/../../etc").is_none());
+        }
+
+        #[test]
+        fn url_to_path_rejects_absolute_id() {
+            let root = TempDir::new();
+            fs::create_dir(root.join("addon")).unwrap();
+            // An "absolute" id joined onto `root` replaces it wholesale per
+            // `Path::join`'s documented behavior, landing outside `root` once
+            // canonicalized (unless `root` itself happens to be `/`).
+            assert!(url_to_path(&root, "/etc/passwd").is_none());
+        }
+
+        #[test]
+        fn url_to_path_rejects_symlink_out_of_root() {
+            let root = TempDir::new();
+            let outside = TempDir::new();
+            fs::write(outside.join("secret"), b"nope").unwrap();
+            #[cfg(unix)]
+            {
+                std::os::unix::fs::symlink(&*outside, root.join("escape")).unwrap();
+                assert!(url_to_path(&root, "escape").is_none());
+            }
+        }
+
+        #[test]
+        fn url_to_path_accepts_id_under_root() {
+            let root = TempDir::new();
+            fs::create_dir(root.join("my.addon")).unwrap();
+            let resolved = url_to_path(&root, "my.addon").expect("id under root should resolve");
+            assert_eq!(resolved, root.join("my.addon").canonicalize().unwrap());
+        }
+
+        #[test]
+        #[cfg(unix)]
+        fn hash_tree_does_not_follow_symlink_out_of_tree() {
+            let outside = TempDir::new();
+            fs::write(outside.join("secret"), b"outside contents").unwrap();
+            let addon = TempDir::new();
+            fs::write(addon.join("addon.xml"), b"<addon/>").unwrap();
+            std::os::unix::fs::symlink(outside.join("secret"), addon.join("escape")).unwrap();
+
+            let with_symlink = hash_tree("id", &addon).unwrap();
+            fs::remove_file(addon.join("escape")).unwrap();
+            let without_symlink = hash_tree("id", &addon).unwrap();
+            // The symlink must be excluded from the cache key entirely, not just
+            // hashed by a different (e.g. un-dereferenced) representation —
+            // removing it shouldn't change the digest.
+            assert_eq!(with_symlink, without_symlink);
+        }
+
+        #[test]
+        #[cfg(unix)]
+        fn build_addon_zip_does_not_follow_symlink_out_of_tree() {
+            let outside = TempDir::new();
+            fs::write(outside.join("secret"), b"outside contents").unwrap();
+            let addon = TempDir::new();
+            fs::write(addon.join("addon.xml"), b"<addon/>").unwrap();
+            std::os::unix::fs::symlink(outside.join("secret"), addon.join("escape")).unwrap();
+
+            let bytes = build_addon_zip("id", &addon).unwrap();
+            let mut archive = zip::ZipArchive::new(std::io::Cursor::new(bytes)).unwrap();
+            for i in 0..archive.len() {
+                let file = archive.by_index(i).unwrap();
+                assert_ne!(file.name(), "id/escape", "symlinked file leaked into the zip");
+            }
+        }
+
+        fn net(s: &str) -> ipnet::IpNet {
+            s.parse().unwrap()
+        }
+
+        fn addr(s: &str) -> IpAddr {
+            s.parse().unwrap()
+        }
+
+        #[test]
+        fn acl_empty_allow_means_all() {
+            let acl = Acl { allow: vec![], deny: vec![], trust_proxy: false };
+            assert!(acl.permits(addr("203.0.113.7")));
+        }
+
+        #[test]
+        fn acl_deny_overrides_allow() {
+            let acl = Acl {
+                allow: vec![net("203.0.113.0/24")],
+                deny: vec![net("203.0.113.7/32")],
+                trust_proxy: false,
+            };
+            assert!(!acl.permits(addr("203.0.113.7")));
+            assert!(acl.permits(addr("203.0.113.8")));
+        }
+
+        #[test]
+        fn acl_allow_list_restricts() {
+            let acl = Acl {
+                allow: vec![net("203.0.113.0/24")],
+                deny: vec![],
+                trust_proxy: false,
+            };
+            assert!(acl.permits(addr("203.0.113.1")));
+            assert!(!acl.permits(addr("198.51.100.1")));
+        }
+
+        #[test]
+        fn resolve_client_addr_picks_rightmost_xff_when_trusted() {
+            let remote: SocketAddr = "192.0.2.1:9001".parse().unwrap();
+            let resolved = resolve_client_addr(Some(remote), Some("203.0.113.1, 198.51.100.2"), true);
+            assert_eq!(resolved, Some(addr("198.51.100.2")));
+        }
+
+        #[test]
+        fn resolve_client_addr_falls_back_to_socket_on_malformed_xff() {
+            let remote: SocketAddr = "192.0.2.1:9001".parse().unwrap();
+            let resolved = resolve_client_addr(Some(remote), Some("not an address"), true);
+            assert_eq!(resolved, Some(addr("192.0.2.1")));
+        }
+
+        #[test]
+        fn resolve_client_addr_ignores_xff_when_not_trusted() {
+            let remote: SocketAddr = "192.0.2.1:9001".parse().unwrap();
+            let resolved = resolve_client_addr(Some(remote), Some("198.51.100.2"), false);
+            assert_eq!(resolved, Some(addr("192.0.2.1")));
+        }
+    }
 }
 
 #[cfg(not(feature = "server"))]
-fn serve(_addons_dir: &Path, _listing: &Path, _cache_dir: &Path) {
-    panic!("this binary does not include server functionality")
+fn serve(_addons_dir: &Path, _listing: &Path, _cache_dir: &Path, _bind: std::net::SocketAddr, _compression: &[String], _acl: &config::AclConfig) -> anyhow::Result<()> {
+    anyhow::bail!("this binary does not include server functionality")
 }
 
-fn write_listing(addons_dir: &Path, output: &Path) {
-    todo!("generating a listing")
+/// Escape text content for inclusion in an XML document.
+fn escape_text(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
 }
 
-fn main() {
-    // TODO: support non UTF-8 paths
-    let args: Vec<String> = std::env::args().collect();
-    let args: Vec<&str> = args.iter().map(|x| &**x).collect();
-    match args[1..] {
-        // Generate XML listing of addons given addons directory.
-        ["generate", addons_dir, output] => write_listing(Path::new(addons_dir), Path::new(output)),
-        ["server", addons_dir, listing] => serve(Path::new(addons_dir),
-                                                 Path::new(listing),
-                                                 &Path::new(addons_dir).join(".zips")),
-        ["server", addons_dir, listing, cache_dir] => serve(Path::new(addons_dir),
-                                                            Path::new(listing),
-                                                            Path::new(cache_dir)),
-        _ => panic!("wrong args"),
+/// Escape an attribute value for inclusion in an XML document.
+fn escape_attr(s: &str) -> String {
+    escape_text(s).replace('"', "&quot;")
+}
+
+/// Serialize a single DOM element (and its descendants) back to XML text,
+/// independent of whatever document it was parsed from.
+fn serialize_element(elem: sxd_document::dom::Element) -> String {
+    use sxd_document::dom::ChildOfElement;
+    let mut out = String::new();
+    out.push('<');
+    out.push_str(elem.name().local_part());
+    for attr in elem.attributes() {
+        out.push(' ');
+        out.push_str(attr.name().local_part());
+        out.push_str("=\"");
+        out.push_str(&escape_attr(attr.value()));
+        out.push('"');
+    }
+    let children = elem.children();
+    if children.is_empty() {
+        out.push_str("/>");
+        return out;
+    }
+    out.push('>');
+    for child in children {
+        match child {
+            ChildOfElement::Element(child) => out.push_str(&serialize_element(child)),
+            ChildOfElement::Text(text) => out.push_str(&escape_text(text.text())),
+            ChildOfElement::Comment(comment) => {
+                out.push_str("<!--");
+                out.push_str(comment.text());
+                out.push_str("-->");
+            }
+            ChildOfElement::ProcessingInstruction(pi) => {
+                out.push_str("<?");
+                out.push_str(pi.target());
+                if let Some(value) = pi.value() {
+                    out.push(' ');
+                    out.push_str(value);
+                }
+                out.push_str("?>");
+            }
+        }
+    }
+    out.push_str("</");
+    out.push_str(elem.name().local_part());
+    out.push('>');
+    out
+}
+
+/// Parse a single addon's manifest and return its top-level `<addon>` element,
+/// serialized back to XML with the per-file prolog stripped.
+fn read_addon_manifest(manifest: &Path) -> anyhow::Result<String> {
+    use anyhow::Context;
+    let package = sxd_document::parser::parse(
+        &fs::read_to_string(manifest).with_context(|| format!("couldn't read addon manifest {}", manifest.display()))?)
+        .with_context(|| format!("addon manifest {} was invalid XML", manifest.display()))?;
+    let document = package.as_document();
+    let value = sxd_xpath::evaluate_xpath(&document, ADDON_XPATH)
+        .with_context(|| format!("failed XPath evaluation of {:?} on {}", ADDON_XPATH, manifest.display()))?;
+    use sxd_xpath::Value;
+    use sxd_xpath::nodeset::Node;
+    match value {
+        Value::Nodeset(set) => {
+            let node = set.iter().next()
+                .with_context(|| format!("manifest {} has no top-level <addon> element", manifest.display()))?;
+            match node {
+                Node::Element(elem) => Ok(serialize_element(elem)),
+                node => anyhow::bail!("invalid node type from xpath evaluation: {:?}", node),
+            }
+        }
+        val => anyhow::bail!("invalid value type from xpath evaluation: {:?}", val),
+    }
+}
+
+/// Read an addon's `version` attribute out of its manifest, for use in the
+/// Kodi-conventional `{id}-{version}.zip` archive name.
+fn addon_version(manifest: &Path) -> anyhow::Result<String> {
+    use anyhow::Context;
+    let package = sxd_document::parser::parse(
+        &fs::read_to_string(manifest).with_context(|| format!("couldn't read addon manifest {}", manifest.display()))?)
+        .with_context(|| format!("addon manifest {} was invalid XML", manifest.display()))?;
+    let document = package.as_document();
+    let value = sxd_xpath::evaluate_xpath(&document, VERSION_XPATH)
+        .with_context(|| format!("failed XPath evaluation of {:?} on {}", VERSION_XPATH, manifest.display()))?;
+    use sxd_xpath::Value;
+    use sxd_xpath::nodeset::Node;
+    match value {
+        Value::Nodeset(set) => {
+            let node = set.iter().next()
+                .with_context(|| format!("manifest {} has no version attribute", manifest.display()))?;
+            match node {
+                Node::Attribute(attr) => Ok(attr.value().to_owned()),
+                node => anyhow::bail!("invalid node type from xpath evaluation: {:?}", node),
+            }
+        }
+        val => anyhow::bail!("invalid value type from xpath evaluation: {:?}", val),
+    }
+}
+
+/// Walk `addons_dir`, concatenate every subdirectory's `addon.xml` into a single
+/// `<addons>` listing, and write it to `output` alongside an `output.md5` digest
+/// file. Kodi clients fetch the digest first and only re-fetch the listing itself
+/// when it changes.
+fn write_listing(addons_dir: &Path, output: &Path) -> anyhow::Result<()> {
+    use anyhow::Context;
+    let mut listing = String::from("<addons>\n");
+    let entries = fs::read_dir(addons_dir)
+        .with_context(|| format!("couldn't read addons directory {}", addons_dir.display()))?;
+    // `fs::read_dir`'s order is platform/filesystem-dependent; sort so that
+    // re-running `generate` with no addon changes reproduces the same
+    // `addons.xml` bytes (and thus the same `addons.xml.md5`) every time.
+    let mut paths: Vec<PathBuf> = entries
+        .map(|entry| entry.context("couldn't read addons directory entry").map(|entry| entry.path()))
+        .collect::<anyhow::Result<_>>()?;
+    paths.sort();
+    for path in paths {
+        if !path.is_dir() {
+            continue;
+        }
+        let manifest = path.join("addon.xml");
+        if !manifest.is_file() {
+            // Not every subdirectory of `addons_dir` is necessarily an addon.
+            continue;
+        }
+        listing.push_str(&read_addon_manifest(&manifest)?);
+        listing.push('\n');
+    }
+    listing.push_str("</addons>\n");
+    fs::write(output, &listing).with_context(|| format!("couldn't write {}", output.display()))?;
+
+    let digest = md5::compute(listing.as_bytes());
+    let md5_path = PathBuf::from(format!("{}.md5", output.display()));
+    fs::write(&md5_path, format!("{:x}", digest)).with_context(|| format!("couldn't write {}", md5_path.display()))?;
+    Ok(())
+}
+
+/// Generate a Kodi repository listing from a directory of addons.
+#[derive(clap::Args, Debug)]
+struct GenerateArgs {
+    /// Directory containing one subdirectory per addon. Falls back to `addons_dir` in the config file.
+    addons_dir: Option<PathBuf>,
+    /// Where to write `addons.xml` (and `addons.xml.md5` alongside it). Falls back to `listing` in the config file.
+    output: Option<PathBuf>,
+}
+
+/// Serve the addon repository over HTTP.
+#[derive(clap::Args, Debug)]
+struct ServerArgs {
+    /// Directory containing one subdirectory per addon. Falls back to `addons_dir` in the config file.
+    addons_dir: Option<PathBuf>,
+    /// Listing file previously produced by `generate`. Falls back to `listing` in the config file.
+    listing: Option<PathBuf>,
+    /// Directory to cache built addon zips in. Falls back to `cache_dir` in the config file, then `addons_dir/.zips`.
+    cache_dir: Option<PathBuf>,
+    /// Address to bind the HTTP server to. Falls back to `bind` in the config file, then `127.0.0.1:9001`.
+    #[arg(long)]
+    bind: Option<std::net::SocketAddr>,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    Generate(GenerateArgs),
+    Server(ServerArgs),
+}
+
+#[derive(clap::Parser, Debug)]
+#[command(about = "Generate and serve a Kodi addon repository")]
+struct Cli {
+    /// TOML configuration file, merged with any of the flags above.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+fn main() -> anyhow::Result<()> {
+    use clap::Parser;
+    let cli = Cli::parse();
+    let file_config = config::Config::load(cli.config.as_deref())?;
+    match cli.command {
+        Command::Generate(args) => {
+            let addons_dir = args.addons_dir.or(file_config.addons_dir)
+                .ok_or_else(|| anyhow::anyhow!("addons_dir must be given on the command line or in the config file"))?;
+            let output = args.output.or(file_config.listing)
+                .ok_or_else(|| anyhow::anyhow!("output must be given on the command line or as `listing` in the config file"))?;
+            write_listing(&addons_dir, &output)?;
+        }
+        Command::Server(args) => {
+            let addons_dir = args.addons_dir.or(file_config.addons_dir)
+                .ok_or_else(|| anyhow::anyhow!("addons_dir must be given on the command line or in the config file"))?;
+            let listing = args.listing.or(file_config.listing)
+                .ok_or_else(|| anyhow::anyhow!("listing must be given on the command line or in the config file"))?;
+            let cache_dir = args.cache_dir.or(file_config.cache_dir).unwrap_or_else(|| addons_dir.join(".zips"));
+            let bind = args.bind.or(file_config.bind).unwrap_or_else(|| "127.0.0.1:9001".parse().unwrap());
+            serve(&addons_dir, &listing, &cache_dir, bind, &file_config.compression, &file_config.acl)?;
+        }
     }
+    Ok(())
 }